@@ -1,18 +1,37 @@
-use std::sync::{Mutex, Condvar};
+use std::sync::{Mutex, MutexGuard, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::rc::Rc;
 use std::cell::UnsafeCell;
 use std::ops::{Deref, DerefMut};
-
-#[allow(dead_code)]
+use std::thread;
+use std::mem;
+use std::time::{Duration, Instant};
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::error::Error;
+
+/// How many readers are currently active against a single partition, plus
+/// the condvars of writers parked waiting for this partition to drain.
 struct Operation {
     active : i32,
-    waiting : Vec<Condvar>,
+    waiting : Vec<Rc<Condvar>>,
 }
-#[allow(dead_code)]
-struct ReadWrite {
-    reader : Operation,
-    writer : Operation,
+
+/// One stripe of reader bookkeeping, keyed by thread id hash.
+struct Partition {
+    state : Mutex<Operation>,
 }
+
+impl Partition {
+    fn new() -> Partition {
+        Partition { state : Mutex::new(Operation { active : 0, waiting : Vec::new() }) }
+    }
+}
+
+/// Number of partitions reader bookkeeping is striped across.
+const NUM_PARTITIONS: usize = 8;
+
 /// Provides a reader-writer lock to protect data of type `T`
 pub struct RwLock<T> {
     lock : Mutex<()>,
@@ -20,8 +39,123 @@ pub struct RwLock<T> {
     global : UnsafeCell<G>,
     pref: Preference,
     order: Order,
+    poisoned: AtomicBool,
+    max_readers: usize,
+    partitions: Vec<Partition>,
+    active_readers: AtomicUsize,
+}
+
+/// A type alias for the result of a lock method which can be poisoned.
+///
+/// The `Ok` variant of this result indicates that the primitive was not
+/// poisoned, and the `Err` variant indicates that it was. Both variants
+/// carry the guard, so callers can still reach possibly-inconsistent data.
+pub type LockResult<G> = Result<G, PoisonError<G>>;
+
+/// A type of error which can be returned whenever a lock is acquired.
+///
+/// This is only returned when a lock is held by a thread that panicked
+/// while the lock was held, leaving the protected data in a potentially
+/// inconsistent state.
+pub struct PoisonError<G> {
+    guard: G,
+}
+
+impl<G> PoisonError<G> {
+    fn new(guard: G) -> PoisonError<G> {
+        PoisonError { guard }
+    }
+
+    /// Consumes this error, returning the underlying guard that allows
+    /// further access to the protected data despite the lock having
+    /// been poisoned.
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+
+    /// Reaches the underlying guard without consuming this error.
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+}
+
+impl<G> fmt::Debug for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        "PoisonError { inner: .. }".fmt(f)
+    }
+}
+
+impl<G> fmt::Display for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        "poisoned lock: another task failed inside".fmt(f)
+    }
+}
+
+impl<G> Error for PoisonError<G> {}
+
+/// A type alias for the result of a non-blocking locking method.
+pub type TryLockResult<G> = Result<G, TryLockError<G>>;
+
+/// An enumeration of possible errors associated with a `TryLockResult`.
+pub enum TryLockError<G> {
+    /// The lock was acquired but is poisoned; the error carries the guard.
+    Poisoned(PoisonError<G>),
+    /// The lock could not be acquired at this time because the operation
+    /// would otherwise block.
+    WouldBlock,
 }
 
+impl<G> fmt::Debug for TryLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryLockError::Poisoned(..) => "Poisoned(..)".fmt(f),
+            TryLockError::WouldBlock => "WouldBlock".fmt(f),
+        }
+    }
+}
+
+impl<G> fmt::Display for TryLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryLockError::Poisoned(ref p) => p.fmt(f),
+            TryLockError::WouldBlock => "try_lock failed because the operation would block".fmt(f),
+        }
+    }
+}
+
+impl<G> Error for TryLockError<G> {}
+
+/// A type alias for the result of a locking method bounded by a timeout.
+pub type TimeoutResult<G> = Result<G, TimeoutError<G>>;
+
+/// An enumeration of possible errors associated with a `TimeoutResult`.
+pub enum TimeoutError<G> {
+    /// The lock was acquired but is poisoned; the error carries the guard.
+    Poisoned(PoisonError<G>),
+    /// The lock could not be acquired within the requested duration.
+    TimedOut,
+}
+
+impl<G> fmt::Debug for TimeoutError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimeoutError::Poisoned(..) => "Poisoned(..)".fmt(f),
+            TimeoutError::TimedOut => "TimedOut".fmt(f),
+        }
+    }
+}
+
+impl<G> fmt::Display for TimeoutError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimeoutError::Poisoned(ref p) => p.fmt(f),
+            TimeoutError::TimedOut => "timed out waiting for the lock".fmt(f),
+        }
+    }
+}
+
+impl<G> Error for TimeoutError<G> {}
+
 #[derive(PartialEq)]
 pub enum Preference {
     /// Readers-preferred
@@ -45,8 +179,8 @@ pub enum Order {
 struct G{
     reader_wait : Vec<Rc<Condvar>>,
     writer_wait :  Vec<Rc<Condvar>>,
-    reader_active : i32,
     writer_active : i32,
+    upgradable_active : bool,
 }  //put all the global variable into a class/struct
 
 impl<T> RwLock<T> {
@@ -55,30 +189,137 @@ impl<T> RwLock<T> {
     /// data: the shared object to be protected by this lock
     /// pref: which preference
     /// order: in which order to wake up the threads waiting on this lock
+    ///
+    /// Places no cap on the number of concurrent readers. Use
+    /// `with_max_readers` to bound concurrent reader access.
     pub fn new(data: T, pref: Preference, order: Order) -> RwLock<T> {
+        RwLock::with_max_readers(data, pref, order, usize::max_value())
+    }
+
+    /// Constructs a new `RwLock` that allows at most `max_readers` readers
+    /// to hold the lock at the same time.
+    ///
+    /// data: the shared object to be protected by this lock
+    /// pref: which preference
+    /// order: in which order to wake up the threads waiting on this lock
+    /// max_readers: the maximum number of concurrent readers allowed, even
+    /// when no writer is active or waiting
+    pub fn with_max_readers(data: T, pref: Preference, order: Order, max_readers: usize) -> RwLock<T> {
         RwLock{
             lock : Mutex::new(()),
             global : UnsafeCell::new({
                 G{
                     reader_wait : Vec::new(),
                     writer_wait : Vec::new(),
-                    reader_active : 0,
                     writer_active : 0,
+                    upgradable_active : false,
                 }
             }),
             data : UnsafeCell::new(data),
             pref : pref,
             order : order,
+            poisoned : AtomicBool::new(false),
+            max_readers : max_readers,
+            partitions : (0..NUM_PARTITIONS).map(|_| Partition::new()).collect(),
+            active_readers : AtomicUsize::new(0),
+
+        }
+
+    }
+
+    /// Hashes the current thread's id to the partition it reads/writes
+    /// reader bookkeeping through.
+    fn partition_index() -> usize {
+        let mut hasher = DefaultHasher::new();
+        thread::current().id().hash(&mut hasher);
+        (hasher.finish() as usize) % NUM_PARTITIONS
+    }
+
+    /// Registers this thread as an active reader in partition `idx`.
+    fn partition_acquire(&self, idx: usize) {
+        let mut state = self.partitions[idx].state.lock().unwrap();
+        state.active += 1;
+        self.active_readers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Releases this thread's reader slot in partition `idx`, waking any
+    /// writer parked draining that partition once it reaches zero.
+    fn partition_release(&self, idx: usize) {
+        let mut state = self.partitions[idx].state.lock().unwrap();
+        if state.active > 0 {
+            state.active -= 1;
+        }
+        self.active_readers.fetch_sub(1, Ordering::SeqCst);
+        if state.active == 0 {
+            for cond_var in &state.waiting {
+                cond_var.notify_all();
+            }
+        }
+    }
 
+    /// Blocks until partition `p` has no active readers, then returns the
+    /// still-held guard so the caller keeps it locked (and new readers
+    /// blocked) for the duration of the write.
+    fn partition_drain<'a>(&self, p: &'a Partition) -> MutexGuard<'a, Operation> {
+        let mut state = p.state.lock().unwrap();
+        while state.active > 0 {
+            let cond_var = Rc::new(Condvar::new());
+            state.waiting.push(cond_var.clone());
+            state = cond_var.wait(state).unwrap();
+            if let Some(pos) = state.waiting.iter().position(|c| Rc::ptr_eq(c, &cond_var)) {
+                state.waiting.remove(pos);
+            }
+        }
+        state
+    }
 
+    /// Like `partition_drain`, but gives up once `deadline` passes.
+    fn partition_drain_timeout<'a>(&self, p: &'a Partition, deadline: Instant) -> Result<MutexGuard<'a, Operation>, ()> {
+        let mut state = p.state.lock().unwrap();
+        loop {
+            if state.active == 0 {
+                return Ok(state);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(());
+            }
+            let cond_var = Rc::new(Condvar::new());
+            state.waiting.push(cond_var.clone());
+            let (s, _timeout) = cond_var.wait_timeout(state, deadline - now).unwrap();
+            state = s;
+            if let Some(pos) = state.waiting.iter().position(|c| Rc::ptr_eq(c, &cond_var)) {
+                state.waiting.remove(pos);
+            }
         }
+    }
 
+    /// Like `partition_drain`, but never blocks: returns `None` if the
+    /// partition is locked or still has an active reader.
+    fn partition_try_drain<'a>(&self, p: &'a Partition) -> Option<MutexGuard<'a, Operation>> {
+        match p.state.try_lock() {
+            Ok(state) => {
+                if state.active == 0 { Some(state) } else { None }
+            },
+            Err(_) => None,
+        }
+    }
+
+    /// Returns `true` if the lock is poisoned.
+    ///
+    /// A lock becomes poisoned whenever a thread panics while holding the
+    /// write lock, which leaves the protected data in a state that may
+    /// violate the data's invariants.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::SeqCst)
     }
 
     /// Requests a read lock, waits when necessary, and wakes up as soon as the lock becomes available.
     ///
-    /// Always returns Ok(_).
-    /// (We declare this return type to be `Result` to be compatible with `std::sync::RwLock`)
+    /// Returns `Err` if the lock is poisoned, i.e. some other thread panicked
+    /// while holding the write lock. The guard is still handed back in the
+    /// `Err` case so callers can decide whether to trust the possibly
+    /// inconsistent data, matching the convention of `std::sync::RwLock`.
 
     // read_wait return wait condition based on different preference
     // parallel read but sequencial write
@@ -88,6 +329,10 @@ impl<T> RwLock<T> {
             let ref writer_wait = (*global).writer_wait;
             let writer_active = (*global).writer_active;
 
+            if self.max_readers != usize::max_value() && self.active_readers.load(Ordering::SeqCst) >= self.max_readers {
+                return true;
+            }
+
             match self.pref {
                 Preference::Reader => {
                     if writer_active > 0 { return true; }
@@ -102,7 +347,8 @@ impl<T> RwLock<T> {
 
     }
 
-    pub fn read(&self) -> Result<RwLockReadGuard<T>, ()> {
+    pub fn read(&self) -> LockResult<RwLockReadGuard<T>> {
+        let idx = RwLock::<T>::partition_index();
         let mut guard = self.lock.lock().unwrap();
         let cond_var = Rc::new(Condvar::new());
         let global = self.global.get();
@@ -113,26 +359,94 @@ impl<T> RwLock<T> {
             guard = cond_var.wait(guard).unwrap();
         }
 
-        match self.order {
-            Order::Fifo => {
-                unsafe{
-                    (*global).reader_wait.remove(0);
-                }
-            },
-            Order::Lifo => {
-                unsafe{
-                    (*global).reader_wait.pop();
-                }
+        unsafe {
+            if let Some(pos) = (*global).reader_wait.iter().position(|c| Rc::ptr_eq(c, &cond_var)) {
+                (*global).reader_wait.remove(pos);
+            }
+        }
+        // Bump the partition count before dropping `guard`, so the cap check
+        // above and this increment stay atomic against a racing reader.
+        self.partition_acquire(idx);
+        drop(guard);
+        let guard = RwLockReadGuard {
+            lock : &self,
+            partition_idx : idx,
+        };
+        if self.poisoned.load(Ordering::SeqCst) {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Attempts to acquire a read lock without blocking.
+    ///
+    /// If the lock cannot be granted immediately, returns
+    /// `Err(TryLockError::WouldBlock)` instead of parking on a `Condvar`.
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<T>> {
+        let idx = RwLock::<T>::partition_index();
+        {
+            let _guard = self.lock.lock().unwrap();
+            if self.read_wait() {
+                return Err(TryLockError::WouldBlock);
             }
+            self.partition_acquire(idx);
         }
-        unsafe{
-            (*global).reader_active += 1;
+        let guard = RwLockReadGuard {
+            lock : &self,
+            partition_idx : idx,
+        };
+        if self.poisoned.load(Ordering::SeqCst) {
+            Err(TryLockError::Poisoned(PoisonError::new(guard)))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Requests a read lock, waiting for at most `dur` before giving up.
+    ///
+    /// On expiry, this thread's `Condvar` is removed from `reader_wait` so
+    /// `notify_others` does not later signal a waiter that already left.
+    pub fn read_timeout(&self, dur: Duration) -> TimeoutResult<RwLockReadGuard<T>> {
+        let idx = RwLock::<T>::partition_index();
+        let mut guard = self.lock.lock().unwrap();
+        let cond_var = Rc::new(Condvar::new());
+        let global = self.global.get();
+        unsafe {
+            (*global).reader_wait.push(cond_var.clone());
+        }
+
+        let start = Instant::now();
+        while self.read_wait() {
+            let elapsed = start.elapsed();
+            if elapsed >= dur {
+                unsafe {
+                    if let Some(pos) = (*global).reader_wait.iter().position(|c| Rc::ptr_eq(c, &cond_var)) {
+                        (*global).reader_wait.remove(pos);
+                    }
+                }
+                return Err(TimeoutError::TimedOut);
+            }
+            let (g, _timeout) = cond_var.wait_timeout(guard, dur - elapsed).unwrap();
+            guard = g;
         }
-        Ok(
-            RwLockReadGuard {
-                lock : &self
+
+        unsafe {
+            if let Some(pos) = (*global).reader_wait.iter().position(|c| Rc::ptr_eq(c, &cond_var)) {
+                (*global).reader_wait.remove(pos);
             }
-        )
+        }
+        self.partition_acquire(idx);
+        drop(guard);
+        let guard = RwLockReadGuard {
+            lock : &self,
+            partition_idx : idx,
+        };
+        if self.poisoned.load(Ordering::SeqCst) {
+            Err(TimeoutError::Poisoned(PoisonError::new(guard)))
+        } else {
+            Ok(guard)
+        }
     }
 
 
@@ -141,7 +455,12 @@ impl<T> RwLock<T> {
     /// * if `order == Order::Fifo`, wakes up the first thread
     /// * if `order == Order::Lifo`, wakes up the last thread
     ///
-    /// Always returns Ok(_).
+    /// Returns `Err` if the lock is poisoned, i.e. some other thread
+    /// panicked while holding the write lock. The guard is still handed
+    /// back in the `Err` case so callers can decide whether to trust the
+    /// possibly inconsistent data.
+    // Arbitrates turns at the control mutex only; active readers are
+    // enforced separately by draining every partition (see `partition_drain`).
     #[allow(unused_variables)]
     fn write_wait(&self) -> bool {
         let global = self.global.get();
@@ -149,46 +468,210 @@ impl<T> RwLock<T> {
             let ref writer_wait = (*global).writer_wait;
             let writer_active = (*global).writer_active;
             let ref reader_wait = (*global).reader_wait;
-            let reader_active = (*global).reader_active;
             match self.pref {
                 Preference::Reader => {
-                    if writer_active > 0 || reader_wait.len() > 0 || reader_active > 0 { return true; }
+                    if writer_active > 0 || reader_wait.len() > 0 { return true; }
                     else{ return false; }
                 },
                 Preference::Writer => {
-                    if writer_active > 0 || reader_active > 0 { return true; }
+                    if writer_active > 0 { return true; }
                     else{ return false; }
                 },
             }
         }
     }
 
-    pub fn write(&self) -> Result<RwLockWriteGuard<T>, ()> {
-        let mut guard = self.lock.lock().unwrap();
-        let cond_var = Rc::new(Condvar::new());
-        let global = self.global.get();
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<T>> {
+        {
+            let mut guard = self.lock.lock().unwrap();
+            let cond_var = Rc::new(Condvar::new());
+            let global = self.global.get();
+
+            unsafe{
+                (*global).writer_wait.push(cond_var.clone());
+                while self.write_wait() {
+                    guard = cond_var.wait(guard).unwrap();
+                }
+                if let Some(pos) = (*global).writer_wait.iter().position(|c| Rc::ptr_eq(c, &cond_var)) {
+                    (*global).writer_wait.remove(pos);
+                }
+                (*global).writer_active += 1;
+            }
+        }
+
+        // Won its turn; now drain every partition before taking the guard.
+        let partition_guards = self.partitions.iter().map(|p| self.partition_drain(p)).collect();
+        let guard = RwLockWriteGuard {
+            lock : &self,
+            partition_guards : partition_guards,
+        };
+        if self.poisoned.load(Ordering::SeqCst) {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Attempts to acquire a write lock without blocking.
+    ///
+    /// If the lock cannot be granted immediately, returns
+    /// `Err(TryLockError::WouldBlock)` instead of parking on a `Condvar`.
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<T>> {
+        {
+            let _guard = self.lock.lock().unwrap();
+            if self.write_wait() {
+                return Err(TryLockError::WouldBlock);
+            }
+            let global = self.global.get();
+            unsafe {
+                (*global).writer_active += 1;
+            }
+        }
+
+        let mut partition_guards = Vec::with_capacity(self.partitions.len());
+        for p in &self.partitions {
+            match self.partition_try_drain(p) {
+                Some(g) => partition_guards.push(g),
+                None => {
+                    partition_guards.clear();
+                    let _guard = self.lock.lock().unwrap();
+                    unsafe {
+                        let global = self.global.get();
+                        if (*global).writer_active > 0 {
+                            (*global).writer_active -= 1;
+                        }
+                    }
+                    self.notify_others();
+                    return Err(TryLockError::WouldBlock);
+                }
+            }
+        }
+
+        let guard = RwLockWriteGuard {
+            lock : &self,
+            partition_guards : partition_guards,
+        };
+        if self.poisoned.load(Ordering::SeqCst) {
+            Err(TryLockError::Poisoned(PoisonError::new(guard)))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Requests a write lock, waiting for at most `dur` before giving up.
+    ///
+    /// On expiry, this thread's `Condvar` is removed from `writer_wait` so
+    /// `notify_others` does not later signal a waiter that already left.
+    pub fn write_timeout(&self, dur: Duration) -> TimeoutResult<RwLockWriteGuard<T>> {
+        let start = Instant::now();
+        {
+            let mut guard = self.lock.lock().unwrap();
+            let cond_var = Rc::new(Condvar::new());
+            let global = self.global.get();
+            unsafe {
+                (*global).writer_wait.push(cond_var.clone());
+            }
 
-        unsafe{
-            (*global).writer_wait.push(cond_var.clone());
             while self.write_wait() {
-                guard = cond_var.wait(guard).unwrap();
+                let elapsed = start.elapsed();
+                if elapsed >= dur {
+                    unsafe {
+                        if let Some(pos) = (*global).writer_wait.iter().position(|c| Rc::ptr_eq(c, &cond_var)) {
+                            (*global).writer_wait.remove(pos);
+                        }
+                    }
+                    return Err(TimeoutError::TimedOut);
+                }
+                let (g, _timeout) = cond_var.wait_timeout(guard, dur - elapsed).unwrap();
+                guard = g;
             }
-            match self.order {
-                Order::Fifo => {
-                    (*global).writer_wait.remove(0);
-                },
-                Order::Lifo => {
-                    (*global).writer_wait.pop();
+
+            unsafe {
+                if let Some(pos) = (*global).writer_wait.iter().position(|c| Rc::ptr_eq(c, &cond_var)) {
+                    (*global).writer_wait.remove(pos);
                 }
+                (*global).writer_active += 1;
             }
-            (*global).writer_active += 1;
         }
 
-        Ok(
-            RwLockWriteGuard {
-                lock : &self
+        // Partition draining is bounded by the same deadline as the wait above.
+        let deadline = start + dur;
+        let mut partition_guards = Vec::with_capacity(self.partitions.len());
+        for p in &self.partitions {
+            match self.partition_drain_timeout(p, deadline) {
+                Ok(g) => partition_guards.push(g),
+                Err(()) => {
+                    partition_guards.clear();
+                    let _guard = self.lock.lock().unwrap();
+                    unsafe {
+                        let global = self.global.get();
+                        if (*global).writer_active > 0 {
+                            (*global).writer_active -= 1;
+                        }
+                    }
+                    self.notify_others();
+                    return Err(TimeoutError::TimedOut);
+                }
             }
-        )
+        }
+
+        let guard = RwLockWriteGuard {
+            lock : &self,
+            partition_guards : partition_guards,
+        };
+        if self.poisoned.load(Ordering::SeqCst) {
+            Err(TimeoutError::Poisoned(PoisonError::new(guard)))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    // an upgradable reader waits exactly like a regular reader, plus it
+    // must wait while another upgradable reader is already active, since
+    // at most one upgradable reader is allowed at a time
+    fn upgradable_read_wait(&self) -> bool {
+        let global = self.global.get();
+        unsafe {
+            if (*global).upgradable_active { return true; }
+        }
+        self.read_wait()
+    }
+
+    /// Requests an upgradable read lock, waits when necessary.
+    ///
+    /// Grants shared read access like `read`, but at most one upgradable
+    /// reader may be active at a time. The returned guard can later be
+    /// traded for a write guard via `RwLockUpgradableReadGuard::try_upgrade`
+    /// without releasing and re-racing for the lock.
+    pub fn upgradable_read(&self) -> LockResult<RwLockUpgradableReadGuard<T>> {
+        let idx = RwLock::<T>::partition_index();
+        let mut guard = self.lock.lock().unwrap();
+        let cond_var = Rc::new(Condvar::new());
+        let global = self.global.get();
+        unsafe {
+            (*global).reader_wait.push(cond_var.clone());
+        }
+        while self.upgradable_read_wait() {
+            guard = cond_var.wait(guard).unwrap();
+        }
+
+        unsafe {
+            if let Some(pos) = (*global).reader_wait.iter().position(|c| Rc::ptr_eq(c, &cond_var)) {
+                (*global).reader_wait.remove(pos);
+            }
+            (*global).upgradable_active = true;
+        }
+        self.partition_acquire(idx);
+        drop(guard);
+        let guard = RwLockUpgradableReadGuard {
+            lock : &self,
+            partition_idx : idx,
+        };
+        if self.poisoned.load(Ordering::SeqCst) {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
     }
 
     pub fn notify_others(&self) {
@@ -264,6 +747,7 @@ unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
 /// A read guard for `RwLock`
 pub struct RwLockReadGuard<'a, T: 'a> {
     lock: &'a RwLock<T>,
+    partition_idx: usize,
 }
 
 /// Provides access to the shared object
@@ -275,25 +759,80 @@ impl<'a, T> Deref for RwLockReadGuard<'a, T> {
 }
 
 /// Releases the read lock
-#[allow(unused_variables)]
 impl<'a, T> Drop for RwLockReadGuard<'a, T> {
     fn drop(&mut self) {
-        let guard = self.lock.lock.lock().unwrap();
+        self.lock.partition_release(self.partition_idx);
+        let _guard = self.lock.lock.lock().unwrap();
+        self.lock.notify_others();
+    }
+}
+
+/// An upgradable read guard for `RwLock`
+///
+/// Behaves like `RwLockReadGuard` in that it grants shared read access, but
+/// at most one upgradable read guard exists at a time, and it can be traded
+/// for a `RwLockWriteGuard` via `try_upgrade` without releasing the lock.
+pub struct RwLockUpgradableReadGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+    partition_idx: usize,
+}
+
+/// Provides access to the shared object
+impl<'a, T> Deref for RwLockUpgradableReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> RwLockUpgradableReadGuard<'a, T> {
+    /// Attempts to atomically upgrade this read guard into a write guard.
+    ///
+    /// Succeeds only if this is the sole remaining reader and no writer has
+    /// already claimed its turn; otherwise the unchanged guard is handed back
+    /// so the caller keeps reading. Because the attempt happens while still
+    /// holding `self.lock`, no other writer can slip in between the read and
+    /// the write the way it could if the caller instead dropped the read
+    /// guard and re-acquired a write lock.
+    pub fn try_upgrade(self) -> Result<RwLockWriteGuard<'a, T>, RwLockUpgradableReadGuard<'a, T>> {
+        let rw_lock = self.lock;
+        let ctrl = rw_lock.lock.lock().unwrap();
+        let writer_claimed = unsafe { (*rw_lock.global.get()).writer_active > 0 };
+        if !writer_claimed && rw_lock.active_readers.load(Ordering::SeqCst) == 1 {
+            rw_lock.partition_release(self.partition_idx);
+            unsafe {
+                let global = rw_lock.global.get();
+                (*global).writer_active += 1;
+                (*global).upgradable_active = false;
+            }
+            drop(ctrl);
+            let partition_guards = rw_lock.partitions.iter().map(|p| rw_lock.partition_drain(p)).collect();
+            mem::forget(self);
+            Ok(RwLockWriteGuard { lock : rw_lock, partition_guards : partition_guards })
+        } else {
+            drop(ctrl);
+            Err(self)
+        }
+    }
+}
+
+/// Releases the upgradable read lock
+impl<'a, T> Drop for RwLockUpgradableReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.partition_release(self.partition_idx);
+        let _guard = self.lock.lock.lock().unwrap();
         unsafe {
             let global = self.lock.global.get();
-            let ref reader_wait = (*global).reader_wait;
-            let reader_active = (*global).reader_active;
-            if reader_active > 0 {
-                (*global).reader_active -= 1;
-            }
-            self.lock.notify_others();
-         }
+            (*global).upgradable_active = false;
+        }
+        self.lock.notify_others();
     }
 }
 
 /// A write guard for `RwLock`
 pub struct RwLockWriteGuard<'a, T: 'a> {
     lock: &'a RwLock<T>,
+    partition_guards: Vec<MutexGuard<'a, Operation>>,
 }
 
 /// Provides access to the shared object
@@ -311,10 +850,16 @@ impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
     }
 }
 
-/// Releases the write lock
+/// Releases the write lock, poisoning it if the current thread is panicking
 #[allow(unused_variables)]
 impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
     fn drop(&mut self) {
+        if thread::panicking() {
+            self.lock.poisoned.store(true, Ordering::SeqCst);
+        }
+        // Release every partition before notifying the control-mutex wait
+        // lists, so a thread woken below finds readers unblocked already.
+        self.partition_guards.clear();
         let guard = self.lock.lock.lock().unwrap();
         unsafe {
             let global = self.lock.global.get();
@@ -326,3 +871,98 @@ impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Barrier, mpsc};
+    use std::sync::atomic::AtomicUsize;
+
+    // try_upgrade used to sum active readers by locking every partition in
+    // order, which a concurrent write() also drains in order while parked on
+    // whichever partition still has a reader; if the upgrader wasn't in
+    // partition 0 the two would deadlock on each other's held partition.
+    // Run it enough times across different thread ids to land on several
+    // partitions, with a timeout so a regression fails the test instead of
+    // hanging the suite.
+    #[test]
+    fn upgradable_read_try_upgrade_does_not_deadlock_with_concurrent_write() {
+        let lock = Arc::new(RwLock::new(0, Preference::Writer, Order::Fifo));
+        for _ in 0..16 {
+            let reader_lock = lock.clone();
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let guard = reader_lock.upgradable_read().unwrap();
+                thread::sleep(Duration::from_millis(20));
+                match guard.try_upgrade() {
+                    Ok(write_guard) => drop(write_guard),
+                    Err(read_guard) => drop(read_guard),
+                }
+                let _ = tx.send(());
+            });
+            let writer_lock = lock.clone();
+            thread::spawn(move || {
+                let _ = writer_lock.write().unwrap();
+            });
+            rx.recv_timeout(Duration::from_secs(2))
+                .expect("try_upgrade deadlocked against a concurrent write()");
+        }
+    }
+
+    // The max_readers cap check and the active_readers increment used to
+    // happen under separate lock acquisitions, letting two threads both pass
+    // the check before either incremented; hammer read() from many threads
+    // and assert the observed concurrency never exceeds the cap.
+    #[test]
+    fn max_readers_cap_is_enforced_under_contention() {
+        const THREADS: usize = 32;
+        const MAX_READERS: usize = 2;
+        let lock = Arc::new(RwLock::with_max_readers(0, Preference::Reader, Order::Fifo, MAX_READERS));
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS).map(|_| {
+            let lock = lock.clone();
+            let active = active.clone();
+            let peak = peak.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                let _guard = lock.read().unwrap();
+                let n = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(n, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(5));
+                active.fetch_sub(1, Ordering::SeqCst);
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let observed = peak.load(Ordering::SeqCst);
+        assert!(observed <= MAX_READERS, "observed concurrency {} exceeded max_readers cap {}", observed, MAX_READERS);
+    }
+
+    // A held writer must never make try_read/read_timeout park, even when
+    // max_readers is set (the cap check used to sweep every partition
+    // mutex, which a writer holds for its whole critical section).
+    #[test]
+    fn try_read_and_read_timeout_do_not_block_on_a_held_writer() {
+        let lock = Arc::new(RwLock::with_max_readers(0, Preference::Writer, Order::Fifo, 1));
+        let writer_lock = lock.clone();
+        let writer = thread::spawn(move || {
+            let _guard = writer_lock.write().unwrap();
+            thread::sleep(Duration::from_millis(300));
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+        assert!(matches!(lock.try_read(), Err(TryLockError::WouldBlock)));
+        assert!(start.elapsed() < Duration::from_millis(150));
+
+        let start = Instant::now();
+        assert!(matches!(lock.read_timeout(Duration::from_millis(50)), Err(TimeoutError::TimedOut)));
+        assert!(start.elapsed() < Duration::from_millis(150));
+
+        writer.join().unwrap();
+    }
+}